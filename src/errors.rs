@@ -0,0 +1,64 @@
+use std::fmt;
+
+use color_eyre::eyre::Report;
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::PyErr;
+
+create_exception!(midas_rs, MidasError, PyException);
+create_exception!(midas_rs, SpawnError, MidasError);
+create_exception!(midas_rs, ParseError, MidasError);
+create_exception!(midas_rs, WriteError, MidasError);
+
+/// Internal error type that remembers which Python exception class a
+/// failure should surface as once it crosses the pyo3 boundary.
+#[derive(Debug)]
+pub(crate) enum MidasRsError {
+    /// Launching the `twxm` subprocess failed.
+    Spawn(Report),
+    /// The column schema or another download option (compression, data
+    /// source, sort/budget combination) could not be parsed or is invalid.
+    /// Rows that fail to parse are counted as rejected, not raised.
+    Parse(Report),
+    /// Writing a parquet batch, finishing a writer, or touching the
+    /// rejected-row sidecar failed.
+    Write(Report),
+    /// Anything else.
+    Other(Report),
+}
+
+impl fmt::Display for MidasRsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Spawn(e) | Self::Parse(e) | Self::Write(e) | Self::Other(e) => {
+                write!(f, "{e}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MidasRsError {}
+
+impl From<Report> for MidasRsError {
+    fn from(err: Report) -> Self {
+        Self::Other(err)
+    }
+}
+
+impl From<std::io::Error> for MidasRsError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Other(Report::from(err))
+    }
+}
+
+impl From<MidasRsError> for PyErr {
+    fn from(err: MidasRsError) -> Self {
+        let message = err.to_string();
+        match err {
+            MidasRsError::Spawn(_) => SpawnError::new_err(message),
+            MidasRsError::Parse(_) => ParseError::new_err(message),
+            MidasRsError::Write(_) => WriteError::new_err(message),
+            MidasRsError::Other(_) => MidasError::new_err(message),
+        }
+    }
+}
@@ -1,17 +1,30 @@
-use color_eyre::Result;
+use std::collections::HashMap;
+
 use pyo3::types::PyModule;
 use pyo3::{pyfunction, pymodule, wrap_pyfunction, PyObject, PyRef, PyResult, Python};
-use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 
+use self::alloc_stats::TrackingAllocator;
+use self::errors::{MidasError, MidasRsError, ParseError, SpawnError, WriteError};
 use self::splitter::DownloadArgs;
 
+mod alloc_stats;
+mod errors;
 mod product;
+mod source;
 mod splitter;
 
+#[global_allocator]
+static ALLOCATOR: TrackingAllocator = TrackingAllocator;
+
 #[pyfunction]
-pub fn download(args: PyRef<DownloadArgs>, base_dir: &str) -> Result<()> {
+pub fn download(
+    args: PyRef<DownloadArgs>,
+    base_dir: &str,
+) -> PyResult<(HashMap<String, (usize, usize)>, usize)> {
     println!("Downloading {:?}", args);
-    args.download(base_dir)
+    let summary = args.download(base_dir)?;
+    Ok((summary, alloc_stats::peak_bytes()))
 }
 
 #[pyfunction]
@@ -20,20 +33,28 @@ pub fn par_download(
     args: PyObject,
     base_dir: &str,
     n_workers: usize,
-) -> Result<()> {
+) -> PyResult<(HashMap<String, (usize, usize)>, usize)> {
     let args = args.extract::<Vec<DownloadArgs>>(py)?;
     rayon::ThreadPoolBuilder::new()
         .num_threads(n_workers)
-        .build_global()?;
-    args.into_par_iter()
-        .try_for_each(|arg| arg.download(base_dir))?;
-    Ok(())
+        .build_global()
+        .map_err(|e| MidasError::new_err(e.to_string()))?;
+    let summaries = args
+        .into_par_iter()
+        .map(|arg| arg.download(base_dir))
+        .collect::<Result<Vec<_>, MidasRsError>>()?;
+    let summary = summaries.into_iter().flatten().collect();
+    Ok((summary, alloc_stats::peak_bytes()))
 }
 
 #[pymodule]
-fn midas_rs(_py: Python, m: &PyModule) -> PyResult<()> {
+fn midas_rs(py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<DownloadArgs>()?;
     m.add_function(wrap_pyfunction!(download, m)?)?;
     m.add_function(wrap_pyfunction!(par_download, m)?)?;
+    m.add("MidasError", py.get_type::<MidasError>())?;
+    m.add("SpawnError", py.get_type::<SpawnError>())?;
+    m.add("ParseError", py.get_type::<ParseError>())?;
+    m.add("WriteError", py.get_type::<WriteError>())?;
     Ok(())
 }
@@ -1,20 +1,22 @@
 use std::collections::BTreeSet;
 use std::error::Error;
 use std::fs::{File, OpenOptions};
+use std::io::Write as _;
 use std::path::PathBuf;
 use std::rc::Rc;
 use std::str::FromStr;
 
 use color_eyre::eyre::eyre;
 use color_eyre::Result;
-use log::warn;
 use polars::datatypes::CategoricalChunkedBuilder;
 use polars::frame::DataFrame;
 use polars::prelude::NamedFromOwned;
 use polars::series::{IntoSeries, Series};
-use polars_io::parquet::{BatchedWriter, ParquetWriter};
+use polars_io::parquet::{BatchedWriter, ParquetCompression, ParquetWriter, ZstdLevel};
 
-type Row<'a> = [&'a str; 15];
+use crate::errors::MidasRsError;
+
+type Row<'a> = Vec<&'a str>;
 
 struct Categorical<'a> {
     cached: BTreeSet<Rc<str>>,
@@ -79,116 +81,327 @@ where
     }
 }
 
-macro_rules! define_columns {
-    ($($name:ident: $type:ty, $index:expr),*) => {
-        struct Columns {
-            $($name: $type),*,
-            len: usize,
+/// The scalar type a column is parsed and stored as, chosen per-feed by the
+/// caller instead of being baked into a fixed layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ColumnKind {
+    Categorical,
+    U32,
+    U64,
+    F32,
+}
+
+impl FromStr for ColumnKind {
+    type Err = color_eyre::eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "categorical" => Ok(Self::Categorical),
+            "u32" => Ok(Self::U32),
+            "u64" => Ok(Self::U64),
+            "f32" => Ok(Self::F32),
+            other => Err(eyre!("unknown column kind: {other}")),
         }
+    }
+}
 
-        impl Columns {
-            fn with_capacity(capacity: usize) -> Self {
-                Self {
-                    $($name: <$type>::with_capacity(capacity)),*,
-                    len: 0,
-                }
-            }
+/// One column of a runtime schema: its output name, the builder it should
+/// use, and which field of the raw (split) row it reads from.
+#[derive(Debug, Clone)]
+pub(crate) struct ColumnSpec {
+    pub(crate) name: String,
+    pub(crate) kind: ColumnKind,
+    pub(crate) source_index: usize,
+}
 
-            fn into_cols(self) -> Vec<Series> {
-                vec![
-                    $(self.$name.into_series(stringify!($name))),*
-                ]
-            }
+enum ColumnBuilder<'a> {
+    Categorical(Categorical<'a>),
+    U32(Numerical<u32>),
+    U64(Numerical<u64>),
+    F32(Numerical<f32>),
+}
 
-            fn push(&mut self, row: Row) -> Result<()> {
-                $(
-                    self.$name.push(row[$index])?;
-                )*
-                self.len += 1;
-                Ok(())
-            }
+impl ColumnBuilder<'_> {
+    fn with_capacity(kind: ColumnKind, capacity: usize) -> Self {
+        match kind {
+            ColumnKind::Categorical => Self::Categorical(Categorical::with_capacity(capacity)),
+            ColumnKind::U32 => Self::U32(Numerical::with_capacity(capacity)),
+            ColumnKind::U64 => Self::U64(Numerical::with_capacity(capacity)),
+            ColumnKind::F32 => Self::F32(Numerical::with_capacity(capacity)),
+        }
+    }
+
+    fn push(&mut self, entry: &str) -> Result<()> {
+        match self {
+            Self::Categorical(c) => c.push(entry),
+            Self::U32(n) => n.push(entry),
+            Self::U64(n) => n.push(entry),
+            Self::F32(n) => n.push(entry),
+        }
+    }
+
+    /// Checks that `entry` would parse for this column's kind without
+    /// storing it, so a whole row can be validated before any builder in
+    /// it is mutated.
+    fn validate(&self, entry: &str) -> Result<()> {
+        match self {
+            Self::Categorical(_) => Ok(()),
+            Self::U32(_) => entry.parse::<u32>().map(|_| ()).map_err(Into::into),
+            Self::U64(_) => entry.parse::<u64>().map(|_| ()).map_err(Into::into),
+            Self::F32(_) => entry.parse::<f32>().map(|_| ()).map_err(Into::into),
+        }
+    }
+
+    fn into_series(self, name: &str) -> Series {
+        match self {
+            Self::Categorical(c) => c.into_series(name),
+            Self::U32(n) => n.into_series(name),
+            Self::U64(n) => n.into_series(name),
+            Self::F32(n) => n.into_series(name),
+        }
+    }
+}
+
+struct Columns {
+    schema: Rc<[ColumnSpec]>,
+    builders: Vec<ColumnBuilder<'static>>,
+    len: usize,
+}
+
+impl Columns {
+    fn with_capacity(schema: Rc<[ColumnSpec]>, capacity: usize) -> Self {
+        let builders = schema
+            .iter()
+            .map(|spec| ColumnBuilder::with_capacity(spec.kind, capacity))
+            .collect();
+        Self {
+            schema,
+            builders,
+            len: 0,
+        }
+    }
+
+    /// Validates every column before touching any builder, so a row that
+    /// fails partway through doesn't leave earlier builders one element
+    /// longer than the rest (which would desync every column after it).
+    fn push(&mut self, row: &Row) -> Result<()> {
+        for (builder, spec) in self.builders.iter().zip(self.schema.iter()) {
+            let entry = row
+                .get(spec.source_index)
+                .ok_or_else(|| eyre!("row has no column at index {}", spec.source_index))?;
+            builder.validate(entry)?;
+        }
+        for (builder, spec) in self.builders.iter_mut().zip(self.schema.iter()) {
+            let entry = row.get(spec.source_index).expect("validated above");
+            builder.push(entry).expect("validated above");
         }
+        self.len += 1;
+        Ok(())
+    }
+
+    fn into_cols(self) -> Vec<Series> {
+        self.builders
+            .into_iter()
+            .zip(self.schema.iter())
+            .map(|(builder, spec)| builder.into_series(&spec.name))
+            .collect()
+    }
+}
+
+/// Resolved Parquet writer settings, parsed once up front so a bad
+/// compression string surfaces before any rows are processed.
+#[derive(Debug, Clone)]
+pub(crate) struct WriteOptions {
+    pub(crate) compression: ParquetCompression,
+    pub(crate) row_group_size: Option<usize>,
+    pub(crate) statistics: bool,
+}
+
+/// Parses a compression name (and, for `zstd`, an optional level) as passed
+/// from Python into the `ParquetCompression` the writer expects.
+pub(crate) fn parse_compression(
+    name: &str,
+    level: Option<i32>,
+) -> Result<ParquetCompression, MidasRsError> {
+    let reject_level = || {
+        Err(MidasRsError::Parse(eyre!(
+            "compression_level is only valid for zstd, not {name}"
+        )))
     };
+    match name {
+        "uncompressed" if level.is_some() => reject_level(),
+        "uncompressed" => Ok(ParquetCompression::Uncompressed),
+        "snappy" if level.is_some() => reject_level(),
+        "snappy" => Ok(ParquetCompression::Snappy),
+        "lz4" if level.is_some() => reject_level(),
+        "lz4" => Ok(ParquetCompression::Lz4Raw),
+        "zstd" => {
+            let level = level
+                .map(ZstdLevel::try_new)
+                .transpose()
+                .map_err(|e| MidasRsError::Parse(eyre!(e)))?;
+            Ok(ParquetCompression::Zstd(level))
+        }
+        other => Err(MidasRsError::Parse(eyre!("unknown compression: {other}"))),
+    }
 }
 
-define_columns! {
-    c1: Categorical<'static>, 0,
-    c2: Numerical<u64>, 1,
-    c3: Numerical<u32>, 2,
-    c4: Numerical<u32>, 3,
-    c5: Categorical<'static>, 4,
-    c6: Categorical<'static>, 5,
-    c7: Numerical<u32>, 6,
-    c8: Numerical<f32>, 7,
-    c9: Categorical<'static>, 8,
-    c10: Categorical<'static>, 9,
-    c11: Numerical<u32>, 10,
-    c12: Numerical<f32>, 11,
-    c13: Categorical<'static>, 12,
-    c14: Categorical<'static>, 13,
-    c15: Categorical<'static>, 14
+/// Controls whether a symbol's output is sorted by timestamp before it's
+/// written. Quotes and trades arrive interleaved and the trade path
+/// reorders/pads fields, so arrival order is not guaranteed monotonic.
+#[derive(Debug, Clone)]
+pub(crate) struct SortOptions {
+    pub(crate) enabled: bool,
+    /// Name of the timestamp column to sort by (e.g. `"c2"`).
+    pub(crate) column: String,
+    /// When `true`, each `capacity`-sized batch is sorted independently,
+    /// which can still split a boundary event across batches. When
+    /// `false`, all of a symbol's rows are buffered and sorted once for a
+    /// fully time-ordered file.
+    pub(crate) within_batches_only: bool,
 }
 
 pub(crate) struct Product {
     path: PathBuf,
     capacity: usize,
+    schema: Rc<[ColumnSpec]>,
     columns: Columns,
     writer: Option<BatchedWriter<File>>,
+    write_rejected: bool,
+    rejected_sink: Option<File>,
+    rows_written: usize,
+    rows_rejected: usize,
+    write_options: WriteOptions,
+    sort_options: SortOptions,
 }
 
 impl Product {
-    pub(crate) fn new(path: PathBuf, capacity: usize) -> Self {
-        let columns = Columns::with_capacity(capacity);
+    pub(crate) fn new(
+        path: PathBuf,
+        capacity: usize,
+        schema: Rc<[ColumnSpec]>,
+        write_rejected: bool,
+        write_options: WriteOptions,
+        sort_options: SortOptions,
+    ) -> Self {
+        let columns = Columns::with_capacity(schema.clone(), capacity);
         Self {
             path,
             capacity,
+            schema,
             columns,
             writer: None,
+            write_rejected,
+            rejected_sink: None,
+            rows_written: 0,
+            rows_rejected: 0,
+            write_options,
+            sort_options,
         }
     }
 
-    pub(crate) fn push(&mut self, row: &str) -> Result<()> {
-        if self.columns.len >= self.capacity {
+    /// Whether a full batch should flush eagerly. When sorting the whole
+    /// file at once, intermediate flushes are skipped so all rows stay
+    /// buffered until `finish`.
+    fn should_flush_early(&self) -> bool {
+        !self.sort_options.enabled || self.sort_options.within_batches_only
+    }
+
+    /// Rows currently buffered in memory, used as a proxy for a product's
+    /// footprint when the global allocator reports over budget and
+    /// `download_impl` needs to pick the largest one to flush.
+    pub(crate) fn buffered_rows(&self) -> usize {
+        self.columns.len
+    }
+
+    /// Whether an out-of-band flush (e.g. from a memory budget) is safe
+    /// right now, without breaking a full-symbol sort's ordering guarantee.
+    pub(crate) fn can_flush_early(&self) -> bool {
+        self.should_flush_early()
+    }
+
+    pub(crate) fn push(&mut self, row: &str) -> Result<(), MidasRsError> {
+        if self.columns.len >= self.capacity && self.should_flush_early() {
             self.write()?;
         }
-        let t = row.get(0..2).unwrap();
-        let row = match t {
-            "F@" => Self::parse_quote(row),
-            "FT" => Self::parse_trade(row),
-            _ => unreachable!(),
+        let parsed = match row.get(0..2) {
+            Some("F@") => Self::parse_quote(row),
+            Some("FT") => Self::parse_trade(row),
+            _ => Err(eyre!("unrecognized row prefix: {row:?}")),
         };
-        match row {
-            Ok(row) => self.columns.push(row)?,
-            Err(e) => println!("Failed to parse row: {e:?}"),
+        let rejected = match &parsed {
+            Ok(cols) => self.columns.push(cols).is_err(),
+            Err(_) => true,
         };
+        if rejected {
+            self.reject(row)?;
+        }
         Ok(())
     }
 
     fn parse_quote(row: &str) -> Result<Row> {
-        row.split(" ")
-            .collect::<Vec<_>>()
-            .try_into()
-            .map_err(|e| eyre!("Failed to parse quote: {e:?}"))
+        Ok(row.split(" ").collect())
     }
 
     fn parse_trade(row: &str) -> Result<Row> {
-        let mut row = row.split(" ").collect::<Vec<_>>();
+        let mut row: Row = row.split(" ").collect();
+        if row.len() < 7 {
+            return Err(eyre!("trade row too short: {} fields", row.len()));
+        }
         row.push(" ");
         row.push(" ");
         row.swap(6, 7);
         row.swap(7, 8);
-        row[10] = "0";
-        row[11] = "0";
-        row.try_into()
-            .map_err(|e| eyre!("Failed to parse trade: {e:?}"))
+        if let Some(v) = row.get_mut(10) {
+            *v = "0";
+        }
+        if let Some(v) = row.get_mut(11) {
+            *v = "0";
+        }
+        Ok(row)
     }
 
-    fn write(&mut self) -> Result<()> {
+    /// Counts a row that failed to parse, optionally appending its raw text
+    /// to a `<symbol>.rejected.txt` sidecar next to the parquet output.
+    fn reject(&mut self, row: &str) -> Result<(), MidasRsError> {
+        self.rows_rejected += 1;
+        if !self.write_rejected {
+            return Ok(());
+        }
+        let sink = match &mut self.rejected_sink {
+            Some(sink) => sink,
+            None => {
+                let path = self.path.with_extension("rejected.txt");
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| MidasRsError::Write(eyre!(e)))?;
+                self.rejected_sink = Some(file);
+                self.rejected_sink.as_mut().unwrap()
+            }
+        };
+        writeln!(sink, "{row}").map_err(|e| MidasRsError::Write(eyre!(e)))
+    }
+
+    /// Flushes any buffered rows for this product to parquet. Called both
+    /// from the normal capacity-based flow and, under memory pressure,
+    /// directly by `download_impl` to release a product's buffers early.
+    pub(crate) fn write(&mut self) -> Result<(), MidasRsError> {
+        if self.columns.len == 0 {
+            return Ok(());
+        }
         let path = self.path.clone();
-        let mut cols = Columns::with_capacity(self.capacity);
+        let mut cols = Columns::with_capacity(self.schema.clone(), self.capacity);
         std::mem::swap(&mut self.columns, &mut cols);
+        let batch_len = cols.len;
         let series = cols.into_cols();
-        let mut df = DataFrame::new(series)?;
+        let mut df = DataFrame::new(series).map_err(|e| MidasRsError::Write(eyre!(e)))?;
+        if self.sort_options.enabled {
+            df = df
+                .sort([self.sort_options.column.as_str()], false, false)
+                .map_err(|e| MidasRsError::Write(eyre!(e)))?;
+        }
         let writer = if let Some(writer) = &mut self.writer {
             writer
         } else {
@@ -196,14 +409,34 @@ impl Product {
                 .create(true)
                 .write(true)
                 .truncate(true)
-                .open(path)?;
-            let writer = ParquetWriter::new(file).batched(&df.schema())?;
+                .open(path)
+                .map_err(|e| MidasRsError::Write(eyre!(e)))?;
+            let writer = ParquetWriter::new(file)
+                .with_compression(self.write_options.compression)
+                .with_row_group_size(self.write_options.row_group_size)
+                .with_statistics(self.write_options.statistics)
+                .batched(&df.schema())
+                .map_err(|e| MidasRsError::Write(eyre!(e)))?;
             self.writer = Some(writer);
             self.writer.as_mut().unwrap()
         };
         writer
             .write_batch(&mut df)
-            .map_err(|e| eyre!("Failed to write batch: {e:?}"))
+            .map_err(|e| MidasRsError::Write(eyre!("Failed to write batch: {e:?}")))?;
+        self.rows_written += batch_len;
+        Ok(())
+    }
+
+    /// Flushes any buffered rows, closes the parquet writer, and returns a
+    /// `(rows_written, rows_rejected)` summary for this symbol.
+    pub(crate) fn finish(&mut self) -> Result<(usize, usize), MidasRsError> {
+        self.write()?;
+        if let Some(writer) = self.writer.take() {
+            writer
+                .finish()
+                .map_err(|e| MidasRsError::Write(eyre!(e)))?;
+        }
+        Ok((self.rows_written, self.rows_rejected))
     }
 }
 
@@ -221,6 +454,8 @@ mod tests {
     use std::io::{self, BufRead, BufReader};
     use std::path::Path;
 
+    use polars_io::parquet::ParquetReader;
+    use polars_io::SerReader;
     use tempfile::tempdir;
 
     use super::*;
@@ -231,6 +466,33 @@ mod tests {
         BufReader::new(file).lines()
     }
 
+    fn test_schema() -> Rc<[ColumnSpec]> {
+        [
+            ("c1", ColumnKind::Categorical, 0),
+            ("c2", ColumnKind::U64, 1),
+            ("c3", ColumnKind::U32, 2),
+            ("c4", ColumnKind::U32, 3),
+            ("c5", ColumnKind::Categorical, 4),
+            ("c6", ColumnKind::Categorical, 5),
+            ("c7", ColumnKind::U32, 6),
+            ("c8", ColumnKind::F32, 7),
+            ("c9", ColumnKind::Categorical, 8),
+            ("c10", ColumnKind::Categorical, 9),
+            ("c11", ColumnKind::U32, 10),
+            ("c12", ColumnKind::F32, 11),
+            ("c13", ColumnKind::Categorical, 12),
+            ("c14", ColumnKind::Categorical, 13),
+            ("c15", ColumnKind::Categorical, 14),
+        ]
+        .into_iter()
+        .map(|(name, kind, source_index)| ColumnSpec {
+            name: name.to_string(),
+            kind,
+            source_index,
+        })
+        .collect()
+    }
+
     #[test]
     fn test_write() -> Result<()> {
         color_eyre::install()?;
@@ -239,10 +501,105 @@ mod tests {
         //let out_dir = tempdir()?;
         let out_dir = Path::new("./test_data");
         let reader = test_iter("spxw.csv");
-        let mut product = Product::new(out_dir.join("test.parquet"), 10);
+        let write_options = WriteOptions {
+            compression: parse_compression("zstd", None)?,
+            row_group_size: None,
+            statistics: true,
+        };
+        let sort_options = SortOptions {
+            enabled: false,
+            column: "c2".to_string(),
+            within_batches_only: true,
+        };
+        let mut product = Product::new(
+            out_dir.join("test.parquet"),
+            10,
+            test_schema(),
+            false,
+            write_options,
+            sort_options,
+        );
         for row in reader {
             product.push(&row?)?;
         }
         Ok(())
     }
+
+    #[test]
+    fn test_reject_counts_and_sidecar() -> Result<()> {
+        let out_dir = tempdir()?;
+        let write_options = WriteOptions {
+            compression: parse_compression("zstd", None)?,
+            row_group_size: None,
+            statistics: true,
+        };
+        let sort_options = SortOptions {
+            enabled: false,
+            column: "c2".to_string(),
+            within_batches_only: true,
+        };
+        let mut product = Product::new(
+            out_dir.path().join("reject.parquet"),
+            10,
+            test_schema(),
+            true,
+            write_options,
+            sort_options,
+        );
+        // Fewer fields than the 15-column schema needs: `columns.push` fails
+        // for a missing `source_index` instead of the row parsing outright.
+        product.push("F@ too few fields only")?;
+        let (rows_written, rows_rejected) = product.finish()?;
+        assert_eq!(rows_written, 0);
+        assert_eq!(rows_rejected, 1);
+        let sidecar =
+            std::fs::read_to_string(out_dir.path().join("reject.rejected.txt")).unwrap();
+        assert!(sidecar.contains("too few fields only"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_compression_rejects_level_on_non_zstd() {
+        assert!(parse_compression("snappy", Some(3)).is_err());
+        assert!(parse_compression("lz4", Some(3)).is_err());
+        assert!(parse_compression("uncompressed", Some(3)).is_err());
+        assert!(parse_compression("zstd", Some(3)).is_ok());
+    }
+
+    fn quote_row(timestamp: u64) -> String {
+        format!("F@A {timestamp} 1 2 cat TESTSYM 3 1.5 cat2 cat3 4 2.5 cat4 cat5 cat6")
+    }
+
+    #[test]
+    fn test_full_file_sort_orders_by_timestamp() -> Result<()> {
+        let out_dir = tempdir()?;
+        let write_options = WriteOptions {
+            compression: parse_compression("zstd", None)?,
+            row_group_size: None,
+            statistics: true,
+        };
+        let sort_options = SortOptions {
+            enabled: true,
+            column: "c2".to_string(),
+            // Two small batches (capacity 2, 4 rows) would each sort fine on
+            // their own but still interleave across the batch boundary -
+            // only a full-file sort guarantees a globally ordered output.
+            within_batches_only: false,
+        };
+        let path = out_dir.path().join("sorted.parquet");
+        let mut product = Product::new(path.clone(), 2, test_schema(), false, write_options, sort_options);
+        for ts in [30, 10, 40, 20] {
+            product.push(&quote_row(ts))?;
+        }
+        product.finish()?;
+
+        let file = File::open(&path)?;
+        let df = ParquetReader::new(file)
+            .finish()
+            .map_err(|e| eyre!(e))?;
+        let c2 = df.column("c2")?.u64()?;
+        let timestamps: Vec<u64> = c2.into_no_null_iter().collect();
+        assert_eq!(timestamps, vec![10, 20, 30, 40]);
+        Ok(())
+    }
 }
@@ -0,0 +1,63 @@
+use std::io;
+use std::path::PathBuf;
+
+/// Where raw feed lines come from: a live `twxm` capture, a previously
+/// captured dump on disk, or lines piped in on stdin. Letting this be a
+/// runtime choice means a raw dump can be re-split into per-symbol parquet
+/// without re-running the downloader.
+pub(crate) enum DataSource {
+    Twxm { date: String, ticker: String },
+    File(PathBuf),
+    Stdin,
+}
+
+/// Iterates the lines of a memory-mapped file without allocating a `String`
+/// per line — each item borrows straight out of the mapping.
+pub(crate) struct MmapLines<'a> {
+    remaining: &'a [u8],
+}
+
+impl<'a> MmapLines<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Self { remaining: bytes }
+    }
+}
+
+impl<'a> Iterator for MmapLines<'a> {
+    type Item = io::Result<&'a str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining.is_empty() {
+            return None;
+        }
+        let (line, rest) = match self.remaining.iter().position(|&b| b == b'\n') {
+            Some(i) => (&self.remaining[..i], &self.remaining[i + 1..]),
+            None => (self.remaining, &b""[..]),
+        };
+        self.remaining = rest;
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        Some(std::str::from_utf8(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mmap_lines_strips_trailing_cr() {
+        let bytes = b"first\r\nsecond\nthird\r\n";
+        let lines: Vec<&str> = MmapLines::new(bytes).map(|l| l.unwrap()).collect();
+        assert_eq!(lines, vec!["first", "second", "third"]);
+    }
+
+    #[test]
+    fn test_mmap_lines_errors_on_invalid_utf8() {
+        let bytes = b"ok\n\xff\xfe\ntail";
+        let mut lines = MmapLines::new(bytes);
+        assert_eq!(lines.next().unwrap().unwrap(), "ok");
+        let err = lines.next().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(lines.next().unwrap().unwrap(), "tail");
+    }
+}
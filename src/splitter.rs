@@ -1,21 +1,27 @@
 use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Write as _};
 use std::path::PathBuf;
 use std::process::{Command, Stdio};
+use std::rc::Rc;
 
+use color_eyre::eyre::eyre;
 use color_eyre::Result;
+use memmap2::Mmap;
 use pyo3::{pyclass, pymethods, FromPyObject};
 
-use crate::product::Product;
+use crate::alloc_stats;
+use crate::errors::MidasRsError;
+use crate::product::{self, ColumnSpec, Product, SortOptions, WriteOptions};
+use crate::source::{DataSource, MmapLines};
 
-fn get_symbol(row: &str) -> String {
-    row.split(" ")
-        .skip(5)
-        .next()
-        .unwrap()
-        .to_string()
-        .replace("_", "")
+/// Extracts the symbol field (the 6th space-separated token) from a raw
+/// feed row. Returns `None` for a blank line, header, or any row truncated
+/// before that field, so the caller can reject it instead of unwrapping.
+fn get_symbol(row: &str) -> Option<String> {
+    let symbol = row.split(" ").nth(5)?;
+    Some(symbol.to_string().replace("_", ""))
 }
 
 #[pyclass]
@@ -29,18 +35,64 @@ pub struct DownloadArgs {
     capacity: usize,
     #[pyo3(get)]
     skip: HashSet<String>,
+    /// Runtime column schema as `(name, kind, source_index)` tuples, where
+    /// `kind` is one of `"categorical"`, `"u32"`, `"u64"`, `"f32"`.
+    #[pyo3(get)]
+    schema: Vec<(String, String, usize)>,
+    /// Whether rejected rows should also be appended to a
+    /// `<symbol>.rejected.txt` sidecar next to each parquet file.
+    #[pyo3(get)]
+    write_rejected: bool,
+    /// Parquet compression codec: `"uncompressed"`, `"snappy"`, `"lz4"`, or
+    /// `"zstd"`.
+    #[pyo3(get)]
+    compression: String,
+    #[pyo3(get)]
+    compression_level: Option<i32>,
+    #[pyo3(get)]
+    row_group_size: Option<usize>,
+    #[pyo3(get)]
+    statistics: bool,
+    /// Row source as a `(kind, path)` pair: `kind` is `"twxm"` (spawn the
+    /// capture tool, using `date`/`ticker` above), `"file"` (memory-map the
+    /// dump at `path`), or `"stdin"` (`path` is ignored).
+    #[pyo3(get)]
+    source: (String, Option<String>),
+    /// Whether to sort each symbol's output by its timestamp column before
+    /// writing, to undo the interleaving of quotes and trades.
+    #[pyo3(get)]
+    sort: bool,
+    #[pyo3(get)]
+    sort_column: String,
+    /// If `false`, buffer the whole symbol instead of sorting only within
+    /// each `capacity`-sized batch, so the final file is fully ordered.
+    #[pyo3(get)]
+    sort_within_batches_only: bool,
+    /// Global resident-byte budget across all in-flight `Product`s. When
+    /// set and exceeded, `download_impl` flushes the largest-footprint
+    /// products to release memory before admitting more rows. `None`
+    /// leaves memory use unbounded (the previous behavior).
+    #[pyo3(get)]
+    max_bytes: Option<usize>,
 }
 
 impl DownloadArgs {
-    fn iter_rows(&self) -> Result<impl Iterator<Item = io::Result<String>>> {
-        let mut cmd = Command::new("twxm")
-            .arg(self.date.as_str())
-            .arg("opra")
-            .arg(format!("{}_*", self.ticker.as_str()))
-            .stdout(Stdio::piped())
-            .spawn()?;
-        let stdout = cmd.stdout.take().unwrap();
-        Ok(BufReader::new(stdout).lines())
+    fn build_source(&self) -> Result<DataSource, MidasRsError> {
+        let (kind, path) = &self.source;
+        match kind.as_str() {
+            "twxm" => Ok(DataSource::Twxm {
+                date: self.date.clone(),
+                ticker: self.ticker.clone(),
+            }),
+            "file" => {
+                let path = path
+                    .clone()
+                    .ok_or_else(|| MidasRsError::Parse(eyre!("file source requires a path")))?;
+                Ok(DataSource::File(PathBuf::from(path)))
+            }
+            "stdin" => Ok(DataSource::Stdin),
+            other => Err(MidasRsError::Parse(eyre!("unknown data source: {other}"))),
+        }
     }
 
     fn create_path(&self, base_dir: &str, symbol: &str) -> Result<PathBuf> {
@@ -54,48 +106,235 @@ impl DownloadArgs {
         Ok(path.join(format!("{symbol}.parquet")))
     }
 
-    pub(crate) fn download_impl<I>(&self, base_dir: &str, row_iter: I) -> Result<()>
+    fn build_schema(&self) -> Result<Rc<[ColumnSpec]>> {
+        self.schema
+            .iter()
+            .map(|(name, kind, source_index)| {
+                Ok(ColumnSpec {
+                    name: name.clone(),
+                    kind: kind.parse()?,
+                    source_index: *source_index,
+                })
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(Into::into)
+    }
+
+    fn build_write_options(&self) -> Result<WriteOptions, MidasRsError> {
+        Ok(WriteOptions {
+            compression: product::parse_compression(&self.compression, self.compression_level)?,
+            row_group_size: self.row_group_size,
+            statistics: self.statistics,
+        })
+    }
+
+    fn build_sort_options(&self) -> SortOptions {
+        SortOptions {
+            enabled: self.sort,
+            column: self.sort_column.clone(),
+            within_batches_only: self.sort_within_batches_only,
+        }
+    }
+
+    /// If `max_bytes` is set and the allocator's live figure is over
+    /// budget, repeatedly flushes the product with the most buffered rows
+    /// until resident memory drops back under budget (or there's nothing
+    /// left to flush).
+    fn enforce_budget(
+        &self,
+        products: &mut HashMap<String, Product>,
+    ) -> Result<(), MidasRsError> {
+        let Some(max_bytes) = self.max_bytes else {
+            return Ok(());
+        };
+        while alloc_stats::current_bytes() > max_bytes {
+            let largest = products
+                .values_mut()
+                .filter(|p| p.buffered_rows() > 0 && p.can_flush_early())
+                .max_by_key(|p| p.buffered_rows());
+            match largest {
+                Some(product) => product.write()?,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends a row that couldn't even be attributed to a symbol to a
+    /// shared `_unparsed.rejected.txt` sidecar, mirroring `Product::reject`
+    /// for rows that never make it far enough to have a `Product`.
+    fn reject_unparsed(
+        &self,
+        base_dir: &str,
+        row: &str,
+        sink: &mut Option<File>,
+    ) -> Result<(), MidasRsError> {
+        if !self.write_rejected {
+            return Ok(());
+        }
+        let sink = match sink {
+            Some(sink) => sink,
+            None => {
+                let path = self
+                    .create_path(base_dir, "_unparsed")?
+                    .with_extension("rejected.txt");
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| MidasRsError::Write(eyre!(e)))?;
+                *sink = Some(file);
+                sink.as_mut().unwrap()
+            }
+        };
+        writeln!(sink, "{row}").map_err(|e| MidasRsError::Write(eyre!(e)))
+    }
+
+    pub(crate) fn download_impl<I, R>(
+        &self,
+        base_dir: &str,
+        row_iter: I,
+    ) -> Result<HashMap<String, (usize, usize)>, MidasRsError>
     where
-        I: Iterator<Item = io::Result<String>>,
+        I: Iterator<Item = io::Result<R>>,
+        R: AsRef<str>,
     {
+        let schema = self.build_schema().map_err(MidasRsError::Parse)?;
+        let write_options = self.build_write_options()?;
+        let sort_options = self.build_sort_options();
+        if self.max_bytes.is_some() && sort_options.enabled && !sort_options.within_batches_only {
+            return Err(MidasRsError::Parse(eyre!(
+                "max_bytes cannot be combined with sort_within_batches_only=false: a budget \
+                 flush would emit a partial, unsorted batch and break the fully time-ordered \
+                 file the full-symbol sort promises"
+            )));
+        }
         let mut products = HashMap::new();
+        let mut unparsed_sink = None;
+        let mut rows_without_symbol = 0usize;
         for row in row_iter {
             let row = row?;
-            let symbol = get_symbol(&row);
+            let row = row.as_ref();
+            let symbol = match get_symbol(row) {
+                Some(symbol) => symbol,
+                None => {
+                    rows_without_symbol += 1;
+                    self.reject_unparsed(base_dir, row, &mut unparsed_sink)?;
+                    continue;
+                }
+            };
             if self.skip.contains(&symbol) {
                 continue;
             }
+            self.enforce_budget(&mut products)?;
             let product = products.entry(symbol.clone()).or_insert(Product::new(
                 self.create_path(base_dir, symbol.as_str())?,
                 self.capacity,
+                schema.clone(),
+                self.write_rejected,
+                write_options.clone(),
+                sort_options.clone(),
             ));
-            product.push(row.as_str())?;
+            product.push(row)?;
         }
-        Ok(())
+        let mut summary = HashMap::with_capacity(products.len());
+        if rows_without_symbol > 0 {
+            summary.insert(String::new(), (0, rows_without_symbol));
+        }
+        for (symbol, mut product) in products {
+            summary.insert(symbol, product.finish()?);
+        }
+        Ok(summary)
     }
 
-    pub(crate) fn download(&self, base_dir: &str) -> Result<()> {
-        let row_iter = self.iter_rows()?;
-        self.download_impl(base_dir, row_iter)
+    pub(crate) fn download(
+        &self,
+        base_dir: &str,
+    ) -> Result<HashMap<String, (usize, usize)>, MidasRsError> {
+        match self.build_source()? {
+            DataSource::Twxm { date, ticker } => {
+                let mut cmd = Command::new("twxm")
+                    .arg(date.as_str())
+                    .arg("opra")
+                    .arg(format!("{ticker}_*"))
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .map_err(|e| MidasRsError::Spawn(eyre!(e)))?;
+                let stdout = cmd.stdout.take().unwrap();
+                self.download_impl(base_dir, BufReader::new(stdout).lines())
+            }
+            DataSource::Stdin => {
+                self.download_impl(base_dir, BufReader::new(io::stdin()).lines())
+            }
+            DataSource::File(path) => {
+                let file = File::open(&path)?;
+                let mmap = unsafe { Mmap::map(&file) }?;
+                self.download_impl(base_dir, MmapLines::new(&mmap))
+            }
+        }
     }
 }
 
 #[pymethods]
 impl DownloadArgs {
     #[new]
-    pub fn new(date: String, ticker: String, capacity: usize, skip: HashSet<String>) -> Self {
+    #[pyo3(signature = (
+        date,
+        ticker,
+        capacity,
+        skip,
+        schema,
+        write_rejected,
+        compression = "zstd".to_string(),
+        compression_level = None,
+        row_group_size = None,
+        statistics = true,
+        source = ("twxm".to_string(), None),
+        sort = false,
+        sort_column = "c2".to_string(),
+        sort_within_batches_only = true,
+        max_bytes = None,
+    ))]
+    pub fn new(
+        date: String,
+        ticker: String,
+        capacity: usize,
+        skip: HashSet<String>,
+        schema: Vec<(String, String, usize)>,
+        write_rejected: bool,
+        compression: String,
+        compression_level: Option<i32>,
+        row_group_size: Option<usize>,
+        statistics: bool,
+        source: (String, Option<String>),
+        sort: bool,
+        sort_column: String,
+        sort_within_batches_only: bool,
+        max_bytes: Option<usize>,
+    ) -> Self {
         Self {
             date,
             ticker,
             capacity,
             skip,
+            schema,
+            write_rejected,
+            compression,
+            compression_level,
+            row_group_size,
+            statistics,
+            source,
+            sort,
+            sort_column,
+            sort_within_batches_only,
+            max_bytes,
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::fs::{File, OpenOptions};
+    use tempfile::tempdir;
 
     use super::*;
 
@@ -110,10 +349,39 @@ mod tests {
         let file = File::open("test_data/spxw.csv")?;
         let mut reader = BufReader::new(file);
         let line = reader.lines().next().unwrap()?;
-        assert_eq!(get_symbol(&line), "SPXW220302C04400000");
+        assert_eq!(get_symbol(&line), Some("SPXW220302C04400000".to_string()));
         Ok(())
     }
 
+    #[test]
+    fn test_get_symbol_rejects_short_row() {
+        assert_eq!(get_symbol(""), None);
+        assert_eq!(get_symbol("F@ a b c d"), None);
+    }
+
+    fn default_schema() -> Vec<(String, String, usize)> {
+        [
+            ("c1", "categorical", 0),
+            ("c2", "u64", 1),
+            ("c3", "u32", 2),
+            ("c4", "u32", 3),
+            ("c5", "categorical", 4),
+            ("c6", "categorical", 5),
+            ("c7", "u32", 6),
+            ("c8", "f32", 7),
+            ("c9", "categorical", 8),
+            ("c10", "categorical", 9),
+            ("c11", "u32", 10),
+            ("c12", "f32", 11),
+            ("c13", "categorical", 12),
+            ("c14", "categorical", 13),
+            ("c15", "categorical", 14),
+        ]
+        .into_iter()
+        .map(|(name, kind, index)| (name.to_string(), kind.to_string(), index))
+        .collect()
+    }
+
     #[test]
     fn test_download() -> Result<()> {
         let iter_fn = || test_iter("spxw.csv");
@@ -122,7 +390,138 @@ mod tests {
             ticker: "placeholder".to_string(),
             capacity: 10000,
             skip: HashSet::new(),
+            schema: default_schema(),
+            write_rejected: false,
+            compression: "zstd".to_string(),
+            compression_level: None,
+            row_group_size: None,
+            statistics: true,
+            source: ("twxm".to_string(), None),
+            sort: false,
+            sort_column: "c2".to_string(),
+            sort_within_batches_only: true,
+            max_bytes: None,
         };
-        args.download_impl("test_data", iter_fn())
+        args.download_impl("test_data", iter_fn())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_download_with_max_bytes() -> Result<()> {
+        let iter_fn = || test_iter("spxw.csv");
+        let args = DownloadArgs {
+            date: "placeholder".to_string(),
+            ticker: "placeholder".to_string(),
+            capacity: 10000,
+            skip: HashSet::new(),
+            schema: default_schema(),
+            write_rejected: false,
+            compression: "zstd".to_string(),
+            compression_level: None,
+            row_group_size: None,
+            statistics: true,
+            source: ("twxm".to_string(), None),
+            sort: false,
+            sort_column: "c2".to_string(),
+            sort_within_batches_only: true,
+            max_bytes: Some(1),
+        };
+        args.download_impl("test_data", iter_fn())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_bytes_rejects_full_file_sort() {
+        let iter_fn = || test_iter("spxw.csv");
+        let args = DownloadArgs {
+            date: "placeholder".to_string(),
+            ticker: "placeholder".to_string(),
+            capacity: 10000,
+            skip: HashSet::new(),
+            schema: default_schema(),
+            write_rejected: false,
+            compression: "zstd".to_string(),
+            compression_level: None,
+            row_group_size: None,
+            statistics: true,
+            source: ("twxm".to_string(), None),
+            sort: true,
+            sort_column: "c2".to_string(),
+            sort_within_batches_only: false,
+            max_bytes: Some(1),
+        };
+        assert!(args.download_impl("test_data", iter_fn()).is_err());
+    }
+
+    #[test]
+    fn test_download_file_source() -> Result<()> {
+        // Mirrors a captured twxm dump: CRLF line endings, a blank line (as
+        // a real capture routinely has), and a single symbol, read via
+        // `DataSource::File` instead of spawning twxm.
+        let in_dir = tempdir()?;
+        let dump_path = in_dir.path().join("dump.csv");
+        let mut dump = File::create(&dump_path)?;
+        write!(
+            dump,
+            "\r\nF@XYZ 1000 1 2 cat TESTSYM 3 1.5 cat2 cat3 4 2.5 cat4 cat5 cat6\r\n"
+        )?;
+        drop(dump);
+
+        let out_dir = tempdir()?;
+        let args = DownloadArgs {
+            date: "placeholder".to_string(),
+            ticker: "placeholder".to_string(),
+            capacity: 10000,
+            skip: HashSet::new(),
+            schema: default_schema(),
+            write_rejected: false,
+            compression: "zstd".to_string(),
+            compression_level: None,
+            row_group_size: None,
+            statistics: true,
+            source: (
+                "file".to_string(),
+                Some(dump_path.to_str().unwrap().to_string()),
+            ),
+            sort: false,
+            sort_column: "c2".to_string(),
+            sort_within_batches_only: true,
+            max_bytes: None,
+        };
+        // The leading blank line has no symbol field at all; it must be
+        // rejected rather than panicking in `get_symbol` and killing the
+        // whole download.
+        let summary = args.download(out_dir.path().to_str().unwrap())?;
+        assert_eq!(summary.get("TESTSYM"), Some(&(1, 0)));
+        assert_eq!(summary.get(""), Some(&(0, 1)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_download_impl_rejects_short_row_without_panicking() -> Result<()> {
+        // Has a symbol field (6th token) but not enough columns for the
+        // 15-column schema, so it's rejected by `Product::push` further
+        // downstream instead of by `get_symbol` up front.
+        let rows: Vec<io::Result<String>> = vec![Ok("F@ a b c d TESTSYM".to_string())];
+        let args = DownloadArgs {
+            date: "placeholder".to_string(),
+            ticker: "placeholder".to_string(),
+            capacity: 10000,
+            skip: HashSet::new(),
+            schema: default_schema(),
+            write_rejected: false,
+            compression: "zstd".to_string(),
+            compression_level: None,
+            row_group_size: None,
+            statistics: true,
+            source: ("twxm".to_string(), None),
+            sort: false,
+            sort_column: "c2".to_string(),
+            sort_within_batches_only: true,
+            max_bytes: None,
+        };
+        let summary = args.download_impl("test_data", rows.into_iter())?;
+        assert_eq!(summary.get("TESTSYM"), Some(&(0, 1)));
+        Ok(())
     }
 }